@@ -37,19 +37,42 @@ use crate::osstrlines;
 use crossbeam_channel as chan;
 use slog_scope::debug;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::process::{ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
 use std::thread;
+use std::time::Duration;
 use vec1::Vec1;
 
+/// Number of attempts (the first one plus up to 9 retries) at registering
+/// a persistent GC root before giving up. Each retry only happens because
+/// we raced a concurrently
+/// running garbage collector, which should release the global GC lock
+/// again within a handful of attempts.
+const GC_ROOT_REGISTER_RETRIES: u32 = 10;
+
 /// Execute Nix commands using a builder-pattern abstraction.
 #[derive(Clone)]
 pub struct CallOpts<'a> {
     input: Input<'a>,
     attribute: Option<String>,
     argstrs: HashMap<String, String>,
+    gc_root: Option<GcRootSpec>,
+}
+
+/// Describes a persistent GC root that should be registered for a build,
+/// instead of the default throwaway `GcRootTempDir`.
+#[derive(Clone, Debug)]
+struct GcRootSpec {
+    /// Path at which the root's symlink should live, e.g.
+    /// `~/.cache/lorri/gc_roots/<project>/build`.
+    name: PathBuf,
+    /// Whether to register an *indirect* root (a pointer to `name` stored
+    /// in `/nix/var/nix/gcroots/auto`) rather than a *direct* root (which
+    /// requires `name` to live inside `/nix/var/nix/gcroots` itself).
+    /// Lorri always wants `true` here.
+    indirect: bool,
 }
 
 /// Which input to give nix.
@@ -89,6 +112,22 @@ impl From<std::ffi::OsString> for StorePath {
 #[derive(Debug)]
 pub struct GcRootTempDir(tempfile::TempDir);
 
+/// A handle to the GC root created by a Nix build, distinguishing the
+/// lifetime semantics a caller gets back.
+#[derive(Debug)]
+pub enum GcRootHandle {
+    /// A throwaway root tied to the lifetime of this value: once it is
+    /// dropped, the build output becomes collectable again.
+    Temp(GcRootTempDir),
+    /// One or more persistent roots (for an indirect root, registered in
+    /// `/nix/var/nix/gcroots/auto`), one per build output (`nix-store
+    /// --add-root` names multi-output siblings `name`, `name-2`, `name-3`,
+    /// ...). Survives process restarts; remove every path by hand (or run
+    /// `nix-collect-garbage -d`, which prunes stale auto roots) to let
+    /// the collector reclaim the build outputs again.
+    Persistent(Vec1<PathBuf>),
+}
+
 impl<'a> CallOpts<'a> {
     /// Create a CallOpts with the Nix expression `expr`.
     ///
@@ -106,6 +145,7 @@ impl<'a> CallOpts<'a> {
             input: Input::Expression(expr),
             attribute: None,
             argstrs: HashMap::new(),
+            gc_root: None,
         }
     }
 
@@ -115,6 +155,7 @@ impl<'a> CallOpts<'a> {
             input: Input::File(nix_file),
             attribute: None,
             argstrs: HashMap::new(),
+            gc_root: None,
         }
     }
 
@@ -163,6 +204,25 @@ impl<'a> CallOpts<'a> {
         self
     }
 
+    /// Register a persistent indirect GC root at `name` for this build,
+    /// instead of the default throwaway `GcRootTempDir`.
+    ///
+    /// `indirect` should almost always be `true`: an indirect root stores
+    /// a pointer to `name` under `/nix/var/nix/gcroots/auto`, so `name`
+    /// itself can live anywhere (e.g. a per-project cache directory). A
+    /// direct (non-indirect) root instead requires `name` to live inside
+    /// `/nix/var/nix/gcroots`.
+    ///
+    /// Once set, `.paths()`/`.path()` (and their `_with_root` siblings)
+    /// return a `GcRootHandle::Persistent` instead of `GcRootHandle::Temp`.
+    pub fn add_root(&mut self, name: &Path, indirect: bool) -> &mut Self {
+        self.gc_root = Some(GcRootSpec {
+            name: name.to_path_buf(),
+            indirect,
+        });
+        self
+    }
+
     /// Evaluate the expression and parameters, and interpret as type T:
     ///
     /// ```rust
@@ -260,7 +320,12 @@ impl<'a> CallOpts<'a> {
     ///    otherwise => panic!(otherwise)
     /// }
     /// ```
-    pub fn path(&self) -> Result<(StorePath, GcRootTempDir), BuildError> {
+    ///
+    /// The drop-to-release behaviour above holds for the default
+    /// `GcRootHandle::Temp`. With a root set via `.add_root()`, the handle
+    /// is `GcRootHandle::Persistent` and its roots stay in place after it
+    /// is dropped.
+    pub fn path(&self) -> Result<(StorePath, GcRootHandle), BuildError> {
         let (pathsv1, gc_root) = self.paths()?;
         let mut paths = pathsv1.into_vec();
 
@@ -280,6 +345,15 @@ impl<'a> CallOpts<'a> {
         }
     }
 
+    /// Like `.path()`, but registers a persistent indirect GC root at
+    /// `name` instead of the default throwaway `GcRootTempDir`. Equivalent
+    /// to calling `.add_root(name, true)` followed by `.path()`.
+    pub fn path_with_root(&self, name: &Path) -> Result<(StorePath, GcRootHandle), BuildError> {
+        let mut this = self.clone();
+        this.add_root(name, true);
+        this.path()
+    }
+
     /// Build the expression and return a list of paths to the build results.
     /// Like `.path()`, except it returns all store paths.
     ///
@@ -302,20 +376,32 @@ impl<'a> CallOpts<'a> {
     /// assert!(paths.next().unwrap().contains("hello-"));
     /// drop(gc_root);
     /// ```
-    pub fn paths(&self) -> Result<(Vec1<StorePath>, GcRootTempDir), BuildError> {
-        // TODO: temp_dir writes to /tmp by default, we should
-        // create a wrapper using XDG_RUNTIME_DIR instead,
-        // which is per-user and (on systemd systems) a tmpfs.
-        let gc_root_dir = tempfile::TempDir::new()?;
-
-        let mut cmd = Command::new("nix-build");
+    pub fn paths(&self) -> Result<(Vec1<StorePath>, GcRootHandle), BuildError> {
+        match &self.gc_root {
+            None => self.paths_with_temp_root(),
+            Some(spec) => self.paths_with_persistent_root(spec),
+        }
+    }
 
-        // Create a gc root to the build output
-        cmd.args(&[
-            OsStr::new("--out-link"),
-            gc_root_dir.path().join(Path::new("result")).as_os_str(),
-        ]);
+    /// Like `.paths()`, but registers a persistent indirect GC root at
+    /// `name` instead of the default throwaway `GcRootTempDir`. Equivalent
+    /// to calling `.add_root(name, true)` followed by `.paths()`.
+    pub fn paths_with_root(
+        &self,
+        name: &Path,
+    ) -> Result<(Vec1<StorePath>, GcRootHandle), BuildError> {
+        let mut this = self.clone();
+        this.add_root(name, true);
+        this.paths()
+    }
 
+    /// Build the expression with `--out-link` pointed at `out_link`,
+    /// returning the resulting store paths. Shared by `paths_with_temp_root`
+    /// and `paths_with_persistent_root`, which only differ in what GC root
+    /// they turn the build output into afterwards.
+    fn build_to_out_link(&self, out_link: &Path) -> Result<Vec1<StorePath>, BuildError> {
+        let mut cmd = Command::new("nix-build");
+        cmd.args(&[OsStr::new("--out-link"), out_link.as_os_str()]);
         cmd.args(self.command_arguments());
 
         debug!("nix-build"; "command" => ?cmd);
@@ -326,13 +412,122 @@ impl<'a> CallOpts<'a> {
                 .collect::<Result<Vec<StorePath>, _>>()
         })??;
 
-        if let Ok(vec1) = Vec1::try_from_vec(paths) {
-            Ok((vec1, GcRootTempDir(gc_root_dir)))
-        } else {
-            Err(BuildError::output(
-                "expected exactly one Nix output, got zero".to_string(),
-            ))
+        Vec1::try_from_vec(paths).map_err(|_| {
+            BuildError::output("expected exactly one Nix output, got zero".to_string())
+        })
+    }
+
+    /// Build the expression with a throwaway `--out-link`, producing a
+    /// `GcRootHandle::Temp` that keeps the output alive only until it is
+    /// dropped. This is the behaviour used when no root has been set up
+    /// via `.add_root()`.
+    fn paths_with_temp_root(&self) -> Result<(Vec1<StorePath>, GcRootHandle), BuildError> {
+        // TODO: temp_dir writes to /tmp by default, we should
+        // create a wrapper using XDG_RUNTIME_DIR instead,
+        // which is per-user and (on systemd systems) a tmpfs.
+        let gc_root_dir = tempfile::TempDir::new()?;
+        let out_link = gc_root_dir.path().join(Path::new("result"));
+
+        let paths = self.build_to_out_link(&out_link)?;
+
+        Ok((paths, GcRootHandle::Temp(GcRootTempDir(gc_root_dir))))
+    }
+
+    /// Build the expression, then register `spec` as a persistent GC root
+    /// pointing at the build outputs via `nix-store --realise --add-root`
+    /// (plus `--indirect` when `spec.indirect` is set), retrying if we race a
+    /// concurrently running garbage collector.
+    fn paths_with_persistent_root(
+        &self,
+        spec: &GcRootSpec,
+    ) -> Result<(Vec1<StorePath>, GcRootHandle), BuildError> {
+        // `nix-build` defaults to creating a `result` symlink in the
+        // current directory when given no `--out-link`, which for lorri
+        // means the user's project directory. Point it at a throwaway
+        // tempdir instead, which also keeps the output alive until
+        // `register_root` below has created the durable, named, indirect
+        // root we actually want to keep.
+        let gc_root_dir = tempfile::TempDir::new()?;
+        let out_link = gc_root_dir.path().join(Path::new("result"));
+
+        let paths = self.build_to_out_link(&out_link)?;
+        let roots = self.register_root(&paths, spec)?;
+
+        Ok((paths, GcRootHandle::Persistent(roots)))
+    }
+
+    /// Register a GC root at `spec.name` pointing at `paths` (indirect
+    /// when `spec.indirect` is set, direct otherwise),
+    /// restarting the whole `nix-store --realise --add-root` invocation
+    /// from scratch if it fails for a reason that looks like a race with
+    /// a concurrently running garbage collector, rather than treating
+    /// that as a hard `BuildError`.
+    ///
+    /// The collector holds a global GC lock while it runs; a registration
+    /// attempt that lands while the lock is held can transiently fail
+    /// with `ECONNREFUSED` (the collector has exited, socket gone) or
+    /// `ENOENT` (its lock directory doesn't exist yet). Both clear up on
+    /// their own within a handful of retries.
+    ///
+    /// When `paths` has more than one element, `nix-store --add-root`
+    /// creates one indexed sibling symlink per path (`spec.name`,
+    /// `spec.name-2`, `spec.name-3`, ...), in the order the paths were
+    /// passed on the command line. The returned `Vec1` lists every root
+    /// actually created, in that same order, so callers don't silently
+    /// lose track of (and leak) the indexed siblings.
+    fn register_root(
+        &self,
+        paths: &Vec1<StorePath>,
+        spec: &GcRootSpec,
+    ) -> Result<Vec1<PathBuf>, BuildError> {
+        let mut backoff = Duration::from_millis(100);
+
+        for attempt in 0..GC_ROOT_REGISTER_RETRIES {
+            let mut cmd = Command::new("nix-store");
+            cmd.arg("--realise");
+            for path in paths.iter() {
+                cmd.arg(path.as_path());
+            }
+            cmd.arg("--add-root").arg(&spec.name);
+            if spec.indirect {
+                cmd.arg("--indirect");
+            }
+            cmd.stdout(Stdio::null());
+            cmd.stderr(Stdio::piped());
+
+            debug!("nix-store --realise --add-root"; "command" => ?cmd, "attempt" => attempt);
+
+            let output = cmd.output().map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
+                _ => BuildError::io(e),
+            })?;
+
+            if output.status.success() {
+                return Ok(indexed_root_names(&spec.name, paths.len()));
+            }
+
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+            if attempt + 1 < GC_ROOT_REGISTER_RETRIES
+                && is_transient_gc_race(output.status, &stderr)
+            {
+                debug!(
+                    "nix-store --add-root raced the garbage collector, retrying";
+                    "attempt" => attempt, "backoff_ms" => backoff.as_millis() as u64
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+                continue;
+            }
+
+            return Err(BuildError::exit(
+                &cmd,
+                output.status,
+                stderr.lines().map(OsString::from).collect(),
+            ));
         }
+
+        unreachable!("register_root always returns from within its retry loop")
     }
 
     /// Execute a command (presumably a Nix command :)). stderr output
@@ -427,6 +622,51 @@ impl<'a> CallOpts<'a> {
     }
 }
 
+/// The root paths `nix-store --realise <paths..> --add-root <name>` creates
+/// for a build with `count` outputs: `name` for the first output, then
+/// `name-2`, `name-3`, ... for each subsequent one, in the order the paths
+/// were passed on the command line.
+fn indexed_root_names(name: &Path, count: usize) -> Vec1<PathBuf> {
+    let mut names = Vec1::new(name.to_path_buf());
+    for index in 2..=count {
+        names.push(indexed_root_name(name, index));
+    }
+    names
+}
+
+/// The `index`th (1-based, `index >= 2`) indexed sibling of GC root `name`,
+/// e.g. `indexed_root_name("foo", 2) == "foo-2"`.
+fn indexed_root_name(name: &Path, index: usize) -> PathBuf {
+    let mut file_name = name.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", index));
+    name.with_file_name(file_name)
+}
+
+/// Whether a failed `nix-store --add-root` invocation looks like a
+/// transient race with a concurrently running garbage collector, rather
+/// than a real failure.
+///
+/// `nix-store` exits with status 1 on every ordinary error, so the status
+/// alone can't tell the two apart; we additionally require stderr to name
+/// the GC lock/roots machinery (`/nix/var/nix`, `gcroots`) *and* describe
+/// one of the two ways a mid-collection registration can transiently
+/// fail: the collector has exited and its socket is gone (`Connection
+/// refused`), or its lock directory doesn't exist yet (`No such file or
+/// directory`). A bare `ECONNREFUSED`/`ENOENT` match would also trip on
+/// unrelated, permanent errors (e.g. the requested root's parent
+/// directory missing) that happen to share an errno.
+fn is_transient_gc_race(status: ExitStatus, stderr: &str) -> bool {
+    if status.code() != Some(1) {
+        return false;
+    }
+
+    let mentions_gc_lock = stderr.contains("/nix/var/nix") || stderr.contains("gcroots");
+    let transient_os_error =
+        stderr.contains("Connection refused") || stderr.contains("No such file or directory");
+
+    mentions_gc_lock && transient_os_error
+}
+
 /// Possible error conditions encountered when executing Nix evaluation commands.
 #[derive(Debug)]
 pub enum EvaluationError {
@@ -488,9 +728,65 @@ impl From<BuildError> for OnePathError {
 
 #[cfg(test)]
 mod tests {
-    use super::CallOpts;
+    use super::{indexed_root_names, is_transient_gc_race, CallOpts};
     use std::ffi::OsStr;
-    use std::path::Path;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
+    use std::process::ExitStatus;
+
+    #[test]
+    fn indexed_root_names_single_output() {
+        assert_eq!(
+            indexed_root_names(Path::new("/home/user/.cache/lorri/build"), 1).into_vec(),
+            vec![PathBuf::from("/home/user/.cache/lorri/build")],
+        );
+    }
+
+    #[test]
+    fn indexed_root_names_multiple_outputs() {
+        assert_eq!(
+            indexed_root_names(Path::new("/home/user/.cache/lorri/build"), 3).into_vec(),
+            vec![
+                PathBuf::from("/home/user/.cache/lorri/build"),
+                PathBuf::from("/home/user/.cache/lorri/build-2"),
+                PathBuf::from("/home/user/.cache/lorri/build-3"),
+            ],
+        );
+    }
+
+    #[test]
+    fn transient_gc_race_detected() {
+        assert!(is_transient_gc_race(
+            ExitStatus::from_raw(1 << 8),
+            "error: getting status of '/nix/var/nix/gcroots/auto/abc': No such file or directory"
+        ));
+        assert!(is_transient_gc_race(
+            ExitStatus::from_raw(1 << 8),
+            "error: cannot connect to '/nix/var/nix/daemon-socket/socket': Connection refused"
+        ));
+    }
+
+    #[test]
+    fn transient_gc_race_not_detected_for_unrelated_errors() {
+        // Unrelated build failure: no mention of the GC lock/roots machinery.
+        assert!(!is_transient_gc_race(
+            ExitStatus::from_raw(1 << 8),
+            "error: build of '/nix/store/abc.drv' failed"
+        ));
+        // Mentions the right OS error, but about the caller's own root
+        // path rather than the GC lock -- a permanent config error, not
+        // a race.
+        assert!(!is_transient_gc_race(
+            ExitStatus::from_raw(1 << 8),
+            "error: opening lock file '/no/such/dir/root': No such file or directory"
+        ));
+        // Right message, but a non-1 exit status should never be treated
+        // as the transient GC race.
+        assert!(!is_transient_gc_race(
+            ExitStatus::from_raw(2 << 8),
+            "error: getting status of '/nix/var/nix/gcroots/auto/abc': No such file or directory"
+        ));
+    }
 
     #[test]
     fn cmd_arguments_expression() {